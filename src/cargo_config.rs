@@ -0,0 +1,308 @@
+//! A minimal reader for `.cargo/config.toml` (and the legacy extensionless `.cargo/config`), used
+//! as an opt-in fallback for [`crate::BuildEnv::var`] when a variable isn't set in the process
+//! environment.
+//!
+//! This only understands the small slice of the format this crate actually needs: the `[env]`
+//! table, and `[target.<triple>]` / `[target.'cfg(...)']` tables, each holding plain string
+//! `key = "value"` entries. It's a hand-rolled subset rather than a full TOML parser (no arrays,
+//! inline tables, multi-line strings, etc.) to avoid pulling in a TOML dependency for what is, for
+//! now, a narrow read.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cfg;
+
+/// The merged view of every discovered Cargo config file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CargoConfig {
+    env: BTreeMap<String, String>,
+    target: BTreeMap<String, BTreeMap<String, String>>,
+    files: Vec<PathBuf>,
+}
+
+impl CargoConfig {
+    /// Walk from the current directory up to the filesystem root, and `$CARGO_HOME`, merging
+    /// every `config.toml`/`config` found along the way. Files nearer to the current directory
+    /// win when they define the same key.
+    pub(crate) fn discover() -> CargoConfig {
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        if let Ok(cwd) = env::current_dir() {
+            let mut dir = cwd.as_path();
+            loop {
+                dirs.push(dir.to_owned());
+                match dir.parent() {
+                    Some(parent) => dir = parent,
+                    None => break,
+                }
+            }
+        }
+        // merge from the root down to the current directory, so nearer files win
+        dirs.reverse();
+
+        let mut config = CargoConfig::default();
+        // $CARGO_HOME is merged first, so it has the lowest precedence: a project-local
+        // .cargo/config.toml must be able to override it, same as cargo itself.
+        if let Some(cargo_home) = cargo_home() {
+            config.merge_file(&cargo_home.join("config.toml"));
+            config.merge_file(&cargo_home.join("config"));
+        }
+        for dir in &dirs {
+            config.merge_file(&dir.join(".cargo").join("config.toml"));
+            config.merge_file(&dir.join(".cargo").join("config"));
+        }
+        config
+    }
+
+    /// Parse `path` (if it exists) and merge it in, with its values winning over whatever has
+    /// already been merged.
+    fn merge_file(&mut self, path: &Path) {
+        let Ok(contents) = fs::read_to_string(path) else {
+            return;
+        };
+        let parsed = parse(&contents);
+        self.env.extend(parsed.env);
+        for (section, table) in parsed.target {
+            self.target.entry(section).or_default().extend(table);
+        }
+        self.files.push(path.to_owned());
+    }
+
+    /// The files that were actually read while building this config, in the order they were
+    /// merged.
+    pub(crate) fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(PathBuf::as_path)
+    }
+
+    /// Resolve `var_base` for `target`: first against the `[target.<target>]` section (an exact
+    /// triple match), then against whichever `[target.'cfg(...)']` section matches - exact
+    /// triples take precedence over `cfg(...)` matches, same as cargo - then against the
+    /// top-level `[env]` table.
+    pub(crate) fn lookup(&self, var_base: &str, target: &str) -> Option<OsString> {
+        if let Some(v) = self.target.get(target).and_then(|table| table.get(var_base)) {
+            return Some(OsString::from(v));
+        }
+
+        let facts = cfg::TargetFacts::from_triple(target);
+        for (section, table) in &self.target {
+            let Some(expr_src) = section.strip_prefix("cfg(").and_then(|s| s.strip_suffix(')')) else {
+                continue;
+            };
+            let matches = cfg::parse(expr_src).map(|e| e.eval(&facts)).unwrap_or(false);
+            if matches {
+                if let Some(v) = table.get(var_base) {
+                    return Some(OsString::from(v));
+                }
+            }
+        }
+
+        self.env.get(var_base).map(OsString::from)
+    }
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("CARGO_HOME") {
+        return Some(PathBuf::from(dir));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo"))
+}
+
+#[derive(Debug, Default)]
+struct ParsedFile {
+    env: BTreeMap<String, String>,
+    target: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Parse our supported subset of `config.toml`.
+fn parse(input: &str) -> ParsedFile {
+    let mut parsed = ParsedFile::default();
+    let mut section: Vec<String> = Vec::new();
+
+    for line in input.lines() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(inner) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            section = split_section(inner);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().trim_matches(|c| c == '"' || c == '\'').to_owned();
+        let Some(value) = parse_string_value(value.trim()) else {
+            // not a plain string (array, inline table, bool, ...) - out of scope for this reader
+            continue;
+        };
+
+        match section.as_slice() {
+            [s] if s == "env" => {
+                parsed.env.insert(key, value);
+            }
+            [s, triple] if s == "target" => {
+                parsed.target.entry(triple.clone()).or_default().insert(key, value);
+            }
+            _ => {}
+        }
+    }
+
+    parsed
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+/// Split a `[a.b.'c.d']`-style section header on `.`, respecting quoted segments.
+fn split_section(inner: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut cur = String::new();
+    let mut in_quote: Option<char> = None;
+    for c in inner.chars() {
+        match in_quote {
+            Some(q) if c == q => in_quote = None,
+            Some(_) => cur.push(c),
+            None => match c {
+                '\'' | '"' => in_quote = Some(c),
+                '.' => {
+                    parts.push(cur.trim().to_owned());
+                    cur.clear();
+                }
+                _ => cur.push(c),
+            },
+        }
+    }
+    parts.push(cur.trim().to_owned());
+    parts
+}
+
+fn parse_string_value(value: &str) -> Option<String> {
+    let is_quoted = |q: char| value.len() >= 2 && value.starts_with(q) && value.ends_with(q);
+    if is_quoted('"') || is_quoted('\'') {
+        Some(value[1..value.len() - 1].to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_temp_file(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "build-env-test-{}-{}-{:?}",
+            std::process::id(),
+            name,
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("config.toml")
+    }
+
+    #[test]
+    fn reads_env_table() {
+        let path = unique_temp_file("env-table");
+        fs::write(
+            &path,
+            "[env]\nCC = \"/usr/bin/clang\"\n# a comment\nCFLAGS = 'from-config'\n",
+        )
+        .unwrap();
+
+        let mut config = CargoConfig::default();
+        config.merge_file(&path);
+
+        assert_eq!(
+            config.lookup("CC", "x86_64-unknown-linux-gnu"),
+            Some(OsString::from("/usr/bin/clang"))
+        );
+        assert_eq!(
+            config.lookup("CFLAGS", "x86_64-unknown-linux-gnu"),
+            Some(OsString::from("from-config"))
+        );
+        assert_eq!(config.lookup("AR", "x86_64-unknown-linux-gnu"), None);
+        assert_eq!(config.files().collect::<Vec<_>>(), [path.as_path()]);
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn target_table_wins_over_env_table() {
+        let path = unique_temp_file("target-table");
+        fs::write(
+            &path,
+            "[env]\nCC = \"generic-cc\"\n\n[target.x86_64-unknown-linux-gnu]\nCC = \"linux-cc\"\n",
+        )
+        .unwrap();
+
+        let mut config = CargoConfig::default();
+        config.merge_file(&path);
+
+        assert_eq!(
+            config.lookup("CC", "x86_64-unknown-linux-gnu"),
+            Some(OsString::from("linux-cc"))
+        );
+        assert_eq!(
+            config.lookup("CC", "aarch64-apple-darwin"),
+            Some(OsString::from("generic-cc"))
+        );
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn cfg_target_section() {
+        let path = unique_temp_file("cfg-section");
+        fs::write(
+            &path,
+            "[target.'cfg(all(target_os = \"linux\", target_arch = \"x86_64\"))']\nCFLAGS = \"-DLINUX_X86_64\"\n",
+        )
+        .unwrap();
+
+        let mut config = CargoConfig::default();
+        config.merge_file(&path);
+
+        assert_eq!(
+            config.lookup("CFLAGS", "x86_64-unknown-linux-gnu"),
+            Some(OsString::from("-DLINUX_X86_64"))
+        );
+        assert_eq!(config.lookup("CFLAGS", "aarch64-apple-darwin"), None);
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn exact_triple_section_wins_over_cfg_section() {
+        let path = unique_temp_file("exact-vs-cfg");
+        fs::write(
+            &path,
+            "[target.'cfg(target_os = \"linux\")']\nCC = \"cfg-cc\"\n\n\
+             [target.x86_64-unknown-linux-gnu]\nCC = \"exact-cc\"\n",
+        )
+        .unwrap();
+
+        let mut config = CargoConfig::default();
+        config.merge_file(&path);
+
+        // the exact triple matches too, but it must win over the cfg(...) section regardless of
+        // map iteration order (the section name "cfg(...)" happens to sort before most triples)
+        assert_eq!(
+            config.lookup("CC", "x86_64-unknown-linux-gnu"),
+            Some(OsString::from("exact-cc"))
+        );
+        // no exact section for this target, so the cfg(...) section still applies
+        assert_eq!(
+            config.lookup("CC", "aarch64-unknown-linux-gnu"),
+            Some(OsString::from("cfg-cc"))
+        );
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+}
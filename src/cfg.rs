@@ -0,0 +1,290 @@
+//! A small evaluator for the `cfg(...)` expression grammar used by Cargo's
+//! `[target.'cfg(...)']` tables, so [`crate::BuildEnv::var_cfg`] can pick a fallback value based
+//! on properties of the target triple rather than an exact match on the triple string.
+//!
+//! This only needs to support the handful of keys that are derivable from a target triple itself
+//! (`target_arch`, `target_os`, `target_vendor`, `target_env`, `target_family`,
+//! `target_pointer_width`, `target_endian`, plus the bare `unix`/`windows` flags) - it doesn't
+//! attempt to reproduce the full `rustc --print=cfg` output for every known target.
+
+use std::fmt;
+
+/// A parsed `cfg(...)` expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CfgExpr {
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+    /// A leaf predicate: `key = "value"`, or a bare flag such as `unix` (value is `None`).
+    Predicate { key: String, value: Option<String> },
+}
+
+/// Facts about a target, derived from its triple, that a [`CfgExpr`] is evaluated against.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TargetFacts {
+    pub arch: String,
+    pub vendor: String,
+    pub os: String,
+    pub env: Option<String>,
+    pub family: Option<&'static str>,
+    pub pointer_width: Option<&'static str>,
+    pub endian: Option<&'static str>,
+}
+
+/// The triple's OS component doesn't always match what `cfg(target_os = "...")` expects -
+/// notably Apple's desktop triples spell it `darwin` (e.g. `x86_64-apple-darwin`) where rustc's
+/// `target_os` is `"macos"`.
+fn normalize_os(os: &str) -> &str {
+    match os {
+        "darwin" => "macos",
+        other => other,
+    }
+}
+
+impl TargetFacts {
+    /// Derive target facts from a triple such as `x86_64-unknown-linux-gnu`.
+    pub(crate) fn from_triple(target: &str) -> TargetFacts {
+        let mut parts = target.split('-');
+        let arch = parts.next().unwrap_or_default().to_owned();
+        let vendor = parts.next().unwrap_or_default().to_owned();
+        let os = normalize_os(parts.next().unwrap_or_default()).to_owned();
+        let env = parts.next().map(ToOwned::to_owned);
+
+        let family = if os == "windows" {
+            Some("windows")
+        } else if os.is_empty() || os == "none" {
+            None
+        } else {
+            Some("unix")
+        };
+
+        let pointer_width = match arch.as_str() {
+            "x86_64" | "aarch64" | "powerpc64" | "powerpc64le" | "riscv64" | "riscv64gc"
+            | "mips64" | "mips64el" | "sparc64" | "s390x" | "loongarch64" => Some("64"),
+            "x86" | "i386" | "i586" | "i686" | "arm" | "armv5te" | "armv7" | "armebv7r"
+            | "thumbv7neon" | "mips" | "mipsel" | "powerpc" | "riscv32" | "riscv32gc"
+            | "sparc" => Some("32"),
+            _ => None,
+        };
+
+        let endian = match arch.as_str() {
+            "powerpc" | "powerpc64" | "mips" | "mips64" | "sparc" | "sparc64" | "s390x" => {
+                Some("big")
+            }
+            "" => None,
+            _ => Some("little"),
+        };
+
+        TargetFacts {
+            arch,
+            vendor,
+            os,
+            env,
+            family,
+            pointer_width,
+            endian,
+        }
+    }
+
+    fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        match (key, value) {
+            ("target_arch", Some(v)) => self.arch == v,
+            ("target_vendor", Some(v)) => self.vendor == v,
+            ("target_os", Some(v)) => self.os == v,
+            ("target_env", Some(v)) => self.env.as_deref() == Some(v),
+            ("target_family", Some(v)) => self.family == Some(v),
+            ("target_pointer_width", Some(v)) => self.pointer_width == Some(v),
+            ("target_endian", Some(v)) => self.endian == Some(v),
+            ("unix", None) => self.family == Some("unix"),
+            ("windows", None) => self.family == Some("windows"),
+            _ => false,
+        }
+    }
+}
+
+impl CfgExpr {
+    pub(crate) fn eval(&self, facts: &TargetFacts) -> bool {
+        match self {
+            CfgExpr::All(exprs) => exprs.iter().all(|e| e.eval(facts)),
+            CfgExpr::Any(exprs) => exprs.iter().any(|e| e.eval(facts)),
+            CfgExpr::Not(expr) => !expr.eval(facts),
+            CfgExpr::Predicate { key, value } => facts.matches(key, value.as_deref()),
+        }
+    }
+}
+
+/// An error parsing a `cfg(...)` expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CfgParseError(String);
+
+impl fmt::Display for CfgParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid cfg expression: {}", self.0)
+    }
+}
+
+/// Parse a `cfg(...)` expression such as `all(target_os = "linux", target_arch = "x86_64")`.
+pub(crate) fn parse(input: &str) -> Result<CfgExpr, CfgParseError> {
+    let mut p = Parser {
+        rest: input.trim(),
+    };
+    let expr = p.parse_expr()?;
+    p.skip_ws();
+    if !p.rest.is_empty() {
+        return Err(CfgParseError(format!(
+            "unexpected trailing input: {:?}",
+            p.rest
+        )));
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn err<T>(&self, msg: impl Into<String>) -> Result<T, CfgParseError> {
+        Err(CfgParseError(msg.into()))
+    }
+
+    fn parse_ident(&mut self) -> Result<&'a str, CfgParseError> {
+        self.skip_ws();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return self.err(format!("expected identifier, found {:?}", self.rest));
+        }
+        let (ident, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(ident)
+    }
+
+    fn parse_quoted(&mut self) -> Result<String, CfgParseError> {
+        self.skip_ws();
+        if !self.rest.starts_with('"') {
+            return self.err(format!("expected '\"', found {:?}", self.rest));
+        }
+        let rest = &self.rest[1..];
+        let end = rest
+            .find('"')
+            .ok_or_else(|| CfgParseError("unterminated string".to_owned()))?;
+        let (value, rest) = rest.split_at(end);
+        self.rest = &rest[1..];
+        Ok(value.to_owned())
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), CfgParseError> {
+        self.skip_ws();
+        if let Some(rest) = self.rest.strip_prefix(c) {
+            self.rest = rest;
+            Ok(())
+        } else {
+            self.err(format!("expected {:?}, found {:?}", c, self.rest))
+        }
+    }
+
+    /// Parse a comma-separated list of expressions inside `(...)`.
+    fn parse_expr_list(&mut self) -> Result<Vec<CfgExpr>, CfgParseError> {
+        self.expect('(')?;
+        let mut exprs = Vec::new();
+        loop {
+            exprs.push(self.parse_expr()?);
+            self.skip_ws();
+            if self.rest.starts_with(',') {
+                self.rest = &self.rest[1..];
+                self.skip_ws();
+                if self.rest.starts_with(')') {
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        self.expect(')')?;
+        Ok(exprs)
+    }
+
+    fn parse_expr(&mut self) -> Result<CfgExpr, CfgParseError> {
+        let ident = self.parse_ident()?;
+        self.skip_ws();
+        match ident {
+            "all" => Ok(CfgExpr::All(self.parse_expr_list()?)),
+            "any" => Ok(CfgExpr::Any(self.parse_expr_list()?)),
+            "not" => {
+                self.expect('(')?;
+                let expr = self.parse_expr()?;
+                self.skip_ws();
+                self.expect(')')?;
+                Ok(CfgExpr::Not(Box::new(expr)))
+            }
+            key => {
+                self.skip_ws();
+                if self.rest.starts_with('=') {
+                    self.rest = &self.rest[1..];
+                    let value = self.parse_quoted()?;
+                    Ok(CfgExpr::Predicate {
+                        key: key.to_owned(),
+                        value: Some(value),
+                    })
+                } else {
+                    Ok(CfgExpr::Predicate {
+                        key: key.to_owned(),
+                        value: None,
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_evaluates_leaf() {
+        let expr = parse(r#"target_os = "linux""#).unwrap();
+        let linux = TargetFacts::from_triple("x86_64-unknown-linux-gnu");
+        let darwin = TargetFacts::from_triple("x86_64-apple-darwin");
+        assert!(expr.eval(&linux));
+        assert!(!expr.eval(&darwin));
+    }
+
+    #[test]
+    fn parses_and_evaluates_all() {
+        let expr = parse(r#"all(target_os = "linux", target_arch = "x86_64")"#).unwrap();
+        assert!(expr.eval(&TargetFacts::from_triple("x86_64-unknown-linux-gnu")));
+        assert!(!expr.eval(&TargetFacts::from_triple("aarch64-unknown-linux-gnu")));
+    }
+
+    #[test]
+    fn parses_and_evaluates_any_and_not() {
+        let expr = parse(r#"any(not(unix), target_os = "linux")"#).unwrap();
+        assert!(expr.eval(&TargetFacts::from_triple("x86_64-unknown-linux-gnu")));
+        assert!(expr.eval(&TargetFacts::from_triple("x86_64-pc-windows-msvc")));
+        assert!(!expr.eval(&TargetFacts::from_triple("x86_64-apple-darwin")));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(parse("all(").is_err());
+        assert!(parse("target_os = ").is_err());
+        assert!(parse(r#"target_os = "linux" garbage"#).is_err());
+    }
+
+    #[test]
+    fn apple_darwin_triple_reports_macos() {
+        // rustc's own `target_os` for apple's desktop triples is "macos", not the triple's
+        // literal "darwin" component
+        let expr = parse(r#"target_os = "macos""#).unwrap();
+        assert!(expr.eval(&TargetFacts::from_triple("x86_64-apple-darwin")));
+        assert!(expr.eval(&TargetFacts::from_triple("aarch64-apple-darwin")));
+    }
+}
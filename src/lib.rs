@@ -1,9 +1,63 @@
+mod cargo_config;
+mod cfg;
+
 use std::borrow::ToOwned;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::{any, error, fmt};
 
+/// A source of environment-like variables that a [`BuildEnv`] queries instead of reaching into
+/// the real process environment directly.
+///
+/// The default [`BuildEnv`] constructors use a source backed by `std::env::var_os`, but tests (or
+/// downstream build scripts that already have their configuration in some other object) can
+/// supply their own, e.g. [`MapEnvSource`].
+pub trait EnvSource: fmt::Debug {
+    fn get(&self, key: &OsStr) -> Option<OsString>;
+}
+
+/// The default [`EnvSource`], backed by `std::env::var_os`.
+#[derive(Debug, Clone, Default)]
+struct ProcessEnvSource;
+
+impl EnvSource for ProcessEnvSource {
+    fn get(&self, key: &OsStr) -> Option<OsString> {
+        env::var_os(key)
+    }
+}
+
+/// An in-memory [`EnvSource`] backed by a `HashMap`, useful for tests that want a "virtual"
+/// environment, or for build scripts that already have their configuration in some other object
+/// and would rather hand it to a [`BuildEnv`] than round-trip it through the process environment.
+#[derive(Debug, Clone, Default)]
+pub struct MapEnvSource(HashMap<OsString, OsString>);
+
+impl MapEnvSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<K: Into<OsString>, V: Into<OsString>>(&mut self, key: K, value: V) -> &mut Self {
+        self.0.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl FromIterator<(OsString, OsString)> for MapEnvSource {
+    fn from_iter<T: IntoIterator<Item = (OsString, OsString)>>(iter: T) -> Self {
+        MapEnvSource(iter.into_iter().collect())
+    }
+}
+
+impl EnvSource for MapEnvSource {
+    fn get(&self, key: &OsStr) -> Option<OsString> {
+        self.0.get(key).cloned()
+    }
+}
+
 /**
  * Allow retrieval of values pretaining to a `build` process that may be related to the `target`
  * and/or `host` triple.
@@ -19,6 +73,14 @@ pub struct BuildEnv {
 
     // env vars accessed. note that we use a BTreeSet to get deterministic ordering
     used_env_vars: BTreeSet<OsString>,
+
+    // where env var values actually come from; an `Arc` so `BuildEnv` stays `Clone` regardless of
+    // what's backing the source
+    env: Arc<dyn EnvSource>,
+
+    // populated by `from_env_with_config()`; consulted by `var()`/`var_cfg()` as a fallback after
+    // every env lookup has missed
+    config: Option<Arc<cargo_config::CargoConfig>>,
 }
 
 /// If variable retrieval fails, it will be for one of these reasons
@@ -86,9 +148,25 @@ impl BuildEnv {
             target,
             host,
             used_env_vars: Default::default(),
+            env: Arc::new(ProcessEnvSource),
+            config: None,
         })
     }
 
+    /**
+     * The same as [`from_env()`](Self::from_env), but additionally discovers `.cargo/config.toml`
+     * files - walking from the current directory up to the filesystem root, and `$CARGO_HOME` -
+     * and consults them as a fallback for [`var()`](Self::var) whenever a variable isn't set in
+     * the environment. Nearer config files win when several define the same key. Every file that
+     * was read is recorded so [`cargo_print_used_env_vars()`](Self::cargo_print_used_env_vars) can
+     * additionally emit `cargo:rerun-if-changed=<path>` for it.
+     */
+    pub fn from_env_with_config() -> Result<BuildEnv, VarError<String>> {
+        let mut build_env = Self::from_env()?;
+        build_env.config = Some(Arc::new(cargo_config::CargoConfig::discover()));
+        Ok(build_env)
+    }
+
     /**
      * Construct a BuildEnv where the host and target _may_ be different.
      */
@@ -97,6 +175,8 @@ impl BuildEnv {
             host,
             target,
             used_env_vars: Default::default(),
+            env: Arc::new(ProcessEnvSource),
+            config: None,
         }
     }
 
@@ -108,6 +188,22 @@ impl BuildEnv {
             host: trip.clone(),
             target: trip,
             used_env_vars: Default::default(),
+            env: Arc::new(ProcessEnvSource),
+            config: None,
+        }
+    }
+
+    /**
+     * Construct a BuildEnv where the host and target _may_ be different, querying `source`
+     * instead of the process environment for variable values.
+     */
+    pub fn with_env<S: EnvSource + 'static>(host: String, target: String, source: S) -> BuildEnv {
+        BuildEnv {
+            host,
+            target,
+            used_env_vars: Default::default(),
+            env: Arc::new(source),
+            config: None,
         }
     }
 
@@ -131,37 +227,70 @@ impl BuildEnv {
         self.used_env_vars.iter()
     }
 
-    /// Print the used environment variables in the form interpreted by cargo: `cargo:rerun-if-env-changed=FOO`
+    /// Print the used environment variables in the form interpreted by cargo:
+    /// `cargo:rerun-if-env-changed=FOO`. A used variable name that isn't UTF-8 (cargo's directive
+    /// syntax has no way to represent one) is silently skipped rather than panicking; use
+    /// [`try_cargo_print_used_env_vars()`](Self::try_cargo_print_used_env_vars) to be told about
+    /// that instead.
+    ///
+    /// If this `BuildEnv` was built with [`from_env_with_config()`](Self::from_env_with_config),
+    /// also emits `cargo:rerun-if-changed=<path>` for every Cargo config file that was consulted.
     pub fn cargo_print_used_env_vars(&self) {
         for used in self.used_env_vars() {
-            // NOTE: complains loudly if we use a env-var we can't track because it isn't utf-8
-            println!("cargo:rerun-if-env-changed={}", used.to_str().unwrap());
+            if let Some(name) = used.to_str() {
+                println!("cargo:rerun-if-env-changed={}", name);
+            }
+        }
+        self.print_used_config_files();
+    }
+
+    /// The same as [`cargo_print_used_env_vars()`](Self::cargo_print_used_env_vars), but returns
+    /// an error instead of silently skipping the first used variable name that isn't UTF-8.
+    pub fn try_cargo_print_used_env_vars(&self) -> Result<(), VarError<OsString>> {
+        for used in self.used_env_vars() {
+            match used.to_str() {
+                Some(name) => println!("cargo:rerun-if-env-changed={}", name),
+                None => {
+                    return Err(VarError {
+                        key: used.clone(),
+                        kind: VarErrorKind::NotString(used.clone()),
+                    })
+                }
+            }
+        }
+        self.print_used_config_files();
+        Ok(())
+    }
+
+    /// Emit `cargo:rerun-if-changed=<path>` for every Cargo config file consulted by
+    /// [`from_env_with_config()`](Self::from_env_with_config), if any.
+    fn print_used_config_files(&self) {
+        if let Some(config) = &self.config {
+            for file in config.files() {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
         }
     }
 
+    /// Record `var` as having been consulted, without printing anything. The actual
+    /// `cargo:rerun-if-env-changed=` directives are emitted later, in one pass, by
+    /// [`cargo_print_used_env_vars()`](Self::cargo_print_used_env_vars) (or its fallible
+    /// counterpart) - deferring this means a non-UTF-8 variable name doesn't abort the build
+    /// script the moment it's looked up.
     pub fn mark_used(&mut self, var: OsString) {
-        println!(
-            "cargo:rerun-if-env-changed={}",
-            var.to_str().expect("tried to examine non-utf-8 variable")
-        );
         self.used_env_vars.insert(var);
     }
 
     fn env_one(&mut self, var: OsString) -> Option<OsString> {
-        let v = env::var_os(&var);
+        let v = self.env.get(&var);
         self.mark_used(var);
         v
     }
 
-    /// Query the environment for a value, trying the most specific first, before querying more
-    /// general variables.
-    ///
-    /// 1. `<var>_<target>` - for example, `CC_x86_64-unknown-linux-gnu`
-    /// 2. `<var>_<target_with_underscores>` - for example, `CC_x86_64_unknown_linux_gnu`
-    /// 3. `<build-kind>_<var>` - for example, `HOST_CC` or `TARGET_CFLAGS`
-    /// 4. `<var>` - a plain `CC`, `AR` as above.
-    pub fn var<K: AsRef<OsStr>>(&mut self, var_base: K) -> Option<OsString> {
-        /* try the most specific item to the least specific item */
+    /// The `<var>_<target>`, `<var>_<target_with_underscores>` and `<build-kind>_<var>`
+    /// candidate keys, from most to least specific, used by both [`var()`](Self::var) and
+    /// [`var_cfg()`](Self::var_cfg).
+    fn target_keys<K: AsRef<OsStr>>(&self, var_base: &K) -> [OsString; 3] {
         let target = self.target();
         let host = self.host();
         let kind = if host == target { "HOST" } else { "TARGET" };
@@ -176,12 +305,76 @@ impl BuildEnv {
 
         let mut c: OsString = AsRef::<OsStr>::as_ref(kind).to_owned();
         c.push("_");
-        c.push(&var_base);
+        c.push(var_base);
+
+        [a, b, c]
+    }
+
+    /// Query the environment for a value, trying the most specific first, before querying more
+    /// general variables.
+    ///
+    /// 1. `<var>_<target>` - for example, `CC_x86_64-unknown-linux-gnu`
+    /// 2. `<var>_<target_with_underscores>` - for example, `CC_x86_64_unknown_linux_gnu`
+    /// 3. `<build-kind>_<var>` - for example, `HOST_CC` or `TARGET_CFLAGS`
+    /// 4. `<var>` - a plain `CC`, `AR` as above.
+    /// 5. If built with [`from_env_with_config()`](Self::from_env_with_config), a matching
+    ///    `[target.*]`/`[env]` entry from the discovered Cargo config files.
+    pub fn var<K: AsRef<OsStr>>(&mut self, var_base: K) -> Option<OsString> {
+        /* try the most specific item to the least specific item */
+        let [a, b, c] = self.target_keys(&var_base);
+
+        self.env_one(a)
+            .or_else(|| self.env_one(b))
+            .or_else(|| self.env_one(c))
+            .or_else(|| self.env_one(var_base.as_ref().to_owned()))
+            .or_else(|| self.config_var(var_base.as_ref()))
+    }
+
+    /// The same as [`var()`](Self::var), but additionally falls back to `candidates`: a list of
+    /// `(cfg_expr, value)` pairs, tried in order, where `cfg_expr` is a `cfg(...)` expression
+    /// (such as `cfg(all(target_os = "linux", target_arch = "x86_64"))` or just
+    /// `target_os = "linux"`) evaluated against facts derived from [`target()`](Self::target).
+    /// The value of the first matching expression is used if none of the `<var>_<target>`,
+    /// `<var>_<target_with_underscores>` and `<build-kind>_<var>` lookups succeed, ahead of the
+    /// bare `<var>` fallback and the Cargo config fallback.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any `cfg_expr` in `candidates` fails to parse.
+    pub fn var_cfg<K: AsRef<OsStr>>(
+        &mut self,
+        var_base: K,
+        candidates: &[(&str, OsString)],
+    ) -> Option<OsString> {
+        let [a, b, c] = self.target_keys(&var_base);
 
         self.env_one(a)
             .or_else(|| self.env_one(b))
             .or_else(|| self.env_one(c))
+            .or_else(|| self.match_cfg(candidates))
             .or_else(|| self.env_one(var_base.as_ref().to_owned()))
+            .or_else(|| self.config_var(var_base.as_ref()))
+    }
+
+    fn match_cfg(&self, candidates: &[(&str, OsString)]) -> Option<OsString> {
+        let facts = cfg::TargetFacts::from_triple(self.target());
+        candidates.iter().find_map(|(expr, value)| {
+            // accept both `cfg(<expr>)` and a bare `<expr>`
+            let src = expr
+                .trim()
+                .strip_prefix("cfg(")
+                .and_then(|s| s.strip_suffix(')'))
+                .unwrap_or(*expr);
+            let parsed = cfg::parse(src)
+                .unwrap_or_else(|e| panic!("invalid cfg expression {:?}: {}", expr, e));
+            parsed.eval(&facts).then(|| value.clone())
+        })
+    }
+
+    fn config_var(&self, var_base: &OsStr) -> Option<OsString> {
+        let config = self.config.as_ref()?;
+        let var_base = var_base.to_str()?;
+        config.lookup(var_base, self.target())
     }
 
     /// The same as [`var()`], but converts the return to an OsString and provides a useful error
@@ -198,30 +391,35 @@ impl BuildEnv {
             None => None,
         }
     }
+
+    /// The same as [`var()`], but for variables whose value is an OS-delimited list (`PATH`,
+    /// `PKG_CONFIG_PATH`, library search dirs, ...): splits the resolved value with
+    /// [`std::env::split_paths`], which handles `:` vs `;` per-platform.
+    pub fn var_paths<K: AsRef<OsStr>>(&mut self, var_base: K) -> Option<Vec<PathBuf>> {
+        self.var(var_base)
+            .map(|v| env::split_paths(&v).collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BuildEnv;
+    use super::{BuildEnv, MapEnvSource};
     use std::env;
+    use std::path::PathBuf;
 
-    fn clear(trip: &str, var: &[&str]) {
-        for v in var {
-            env::remove_var(&format!("HOST_{}", v));
-            env::remove_var(&format!("TARGET_{}", v));
-            env::remove_var(&format!("{}_{}", v, trip));
-            env::remove_var(&format!("{}_{}", v, trip.replace("-", "_")));
-            env::remove_var(v);
-        }
+    // Each test builds its own "virtual" environment via `MapEnvSource`, so unlike reading the
+    // real process environment, these can run concurrently without stepping on one another.
+
+    fn env(vars: &[(&str, &str)]) -> MapEnvSource {
+        vars.iter().map(|(k, v)| (k.into(), v.into())).collect()
     }
 
+    #[test]
     fn most_general() {
         let t = "this-is-a-target";
         let cc = "a-cc-value";
-        clear(t, &["CC"]);
-        env::set_var("CC", cc);
 
-        let mut b = BuildEnv::new(t.to_owned());
+        let mut b = BuildEnv::with_env(t.to_owned(), t.to_owned(), env(&[("CC", cc)]));
 
         assert_eq!(b.var_str("CC"), Some(Ok(cc.to_owned())));
         let used_env_vars: Vec<_> = b.used_env_vars().collect();
@@ -234,36 +432,42 @@ mod tests {
                 "HOST_CC"
             ]
         );
-        clear(t, &["CC"]);
     }
 
+    #[test]
     fn exact_target() {
         let t = "this-is-a-target";
         let cc = "a-cc-value";
-        clear(t, &["CC"]);
-
-        env::set_var("CC", "notThis");
-        env::set_var("HOST_CC", "not-this");
-        env::set_var(format!("CC_{}", t), cc);
 
-        let mut b = BuildEnv::new(t.to_owned());
+        let mut b = BuildEnv::with_env(
+            t.to_owned(),
+            t.to_owned(),
+            env(&[
+                ("CC", "notThis"),
+                ("HOST_CC", "not-this"),
+                (&format!("CC_{}", t), cc),
+            ]),
+        );
 
         assert_eq!(b.var_str("CC"), Some(Ok(cc.to_owned())));
         let used_env_vars: Vec<_> = b.used_env_vars().collect();
         assert_eq!(&used_env_vars[..], ["CC_this-is-a-target"]);
-        clear(t, &["CC"]);
     }
 
+    #[test]
     fn underscore_target() {
         let t = "this-is-a-target";
         let cc = "a-cc-value";
-        clear(t, &["CC"]);
 
-        env::set_var("CC", "notThis");
-        env::set_var("HOST_CC", "not-this");
-        env::set_var("CC_this_is_a_target", cc);
-
-        let mut b = BuildEnv::new(t.to_owned());
+        let mut b = BuildEnv::with_env(
+            t.to_owned(),
+            t.to_owned(),
+            env(&[
+                ("CC", "notThis"),
+                ("HOST_CC", "not-this"),
+                ("CC_this_is_a_target", cc),
+            ]),
+        );
 
         assert_eq!(b.var_str("CC"), Some(Ok(cc.to_owned())));
         let used_env_vars: Vec<_> = b.used_env_vars().collect();
@@ -271,18 +475,18 @@ mod tests {
             &used_env_vars[..],
             ["CC_this-is-a-target", "CC_this_is_a_target"]
         );
-        clear(t, &["CC"]);
     }
 
+    #[test]
     fn v_host() {
         let t = "this-is-a-target";
         let cc = "a-cc-value";
-        clear(t, &["CC"]);
-
-        env::set_var("CC", "not-this-value");
-        env::set_var("HOST_CC", cc);
 
-        let mut b = BuildEnv::new(t.to_owned());
+        let mut b = BuildEnv::with_env(
+            t.to_owned(),
+            t.to_owned(),
+            env(&[("CC", "not-this-value"), ("HOST_CC", cc)]),
+        );
 
         assert_eq!(b.var_str("CC"), Some(Ok(cc.to_owned())));
         let used_env_vars: Vec<_> = b.used_env_vars().collect();
@@ -290,22 +494,24 @@ mod tests {
             &used_env_vars[..],
             ["CC_this-is-a-target", "CC_this_is_a_target", "HOST_CC"]
         );
-        clear(t, &["CC"]);
     }
 
+    #[test]
     fn v_target() {
         let t = "this-is-a-target";
         let t2 = "some-target";
         let cc = "a-cc-value";
-        clear(t, &["CC"]);
-        clear(t2, &["CC"]);
-
-        env::set_var("CC", "not-this-value");
-        env::set_var("HOST_CC", "not this!");
-        env::set_var("TARGET_CC", cc);
-        env::set_var(format!("CC_{}", t), "not this either");
 
-        let mut b = BuildEnv::new_cross(t.to_owned(), t2.to_owned());
+        let mut b = BuildEnv::with_env(
+            t.to_owned(),
+            t2.to_owned(),
+            env(&[
+                ("CC", "not-this-value"),
+                ("HOST_CC", "not this!"),
+                ("TARGET_CC", cc),
+                (&format!("CC_{}", t), "not this either"),
+            ]),
+        );
 
         assert_eq!(b.var_str("CC"), Some(Ok(cc.to_owned())));
         let used_env_vars: Vec<_> = b.used_env_vars().collect();
@@ -313,21 +519,87 @@ mod tests {
             &used_env_vars[..],
             ["CC_some-target", "CC_some_target", "TARGET_CC"]
         );
-        clear(t, &["CC"]);
     }
 
-    /* tests are only run in seperate threads, and seperate threads share environment between them.
-     * This causes our tests to fail when run concurrently.
-     *
-     * Workaround this for now by explicitly running them sequentially. Correct fix is probably to
-     * provide a "virtual" environment of sorts.
-     */
     #[test]
-    fn all() {
-        most_general();
-        exact_target();
-        underscore_target();
-        v_host();
-        v_target();
+    fn var_cfg_matches_target() {
+        let t = "x86_64-unknown-linux-gnu";
+        let flags = "-DLINUX_X86_64";
+
+        let mut b = BuildEnv::with_env(t.to_owned(), t.to_owned(), env(&[]));
+
+        let candidates = [
+            (
+                r#"all(target_os = "linux", target_arch = "x86_64")"#,
+                flags.into(),
+            ),
+            (r#"target_os = "macos""#, "-DMACOS".into()),
+        ];
+        assert_eq!(
+            b.var_cfg("CFLAGS", &candidates),
+            Some(std::ffi::OsString::from(flags))
+        );
+    }
+
+    #[test]
+    fn var_cfg_falls_through_to_env() {
+        let t = "aarch64-apple-darwin";
+        let cflags = "-Doverride";
+
+        let mut b = BuildEnv::with_env(t.to_owned(), t.to_owned(), env(&[("CFLAGS", cflags)]));
+
+        let candidates = [(
+            r#"all(target_os = "linux", target_arch = "x86_64")"#,
+            "-DLINUX_X86_64".into(),
+        )];
+        assert_eq!(
+            b.var_cfg("CFLAGS", &candidates),
+            Some(std::ffi::OsString::from(cflags))
+        );
+    }
+
+    #[test]
+    fn var_paths_splits_target_specific_override() {
+        let t = "this-is-a-target";
+        let joined = env::join_paths(["/generic/path", "/generic/other"]).unwrap();
+        let joined_target =
+            env::join_paths(["/target-specific/path", "/target-specific/other"]).unwrap();
+
+        let mut b = BuildEnv::with_env(
+            t.to_owned(),
+            t.to_owned(),
+            env(&[
+                ("PKG_CONFIG_PATH", joined.to_str().unwrap()),
+                (
+                    &format!("PKG_CONFIG_PATH_{}", t),
+                    joined_target.to_str().unwrap(),
+                ),
+            ]),
+        );
+
+        assert_eq!(
+            b.var_paths("PKG_CONFIG_PATH"),
+            Some(vec![
+                PathBuf::from("/target-specific/path"),
+                PathBuf::from("/target-specific/other"),
+            ])
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_used_var_name_does_not_panic() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let bad_name = std::ffi::OsString::from_vec(vec![0xff, 0xfe]);
+
+        let mut b = BuildEnv::with_env("t".to_owned(), "t".to_owned(), env(&[]));
+        b.mark_used(bad_name.clone());
+
+        // doesn't panic, just skips the name it can't print
+        b.cargo_print_used_env_vars();
+
+        let err = b.try_cargo_print_used_env_vars().unwrap_err();
+        assert_eq!(err.key, bad_name);
     }
 }